@@ -0,0 +1,38 @@
+//! Secure storage of provider refresh tokens in the OS keychain.
+//!
+//! Tokens are keyed by a per-provider service name plus the account (email
+//! address) they belong to, so the app can hold several accounts across
+//! several providers without them colliding in the platform secret store.
+
+const SERVICE_PREFIX: &str = "email-dashboard";
+
+fn service_name(provider: &str) -> String {
+    format!("{}-{}", SERVICE_PREFIX, provider)
+}
+
+pub fn store_refresh_token(provider: &str, account: &str, refresh_token: &str) -> Result<(), String> {
+    let entry = keyring::Entry::new(&service_name(provider), account)
+        .map_err(|e| format!("Failed to access keyring: {}", e))?;
+    entry
+        .set_password(refresh_token)
+        .map_err(|e| format!("Failed to store refresh token: {}", e))
+}
+
+pub fn load_refresh_token(provider: &str, account: &str) -> Result<Option<String>, String> {
+    let entry = keyring::Entry::new(&service_name(provider), account)
+        .map_err(|e| format!("Failed to access keyring: {}", e))?;
+    match entry.get_password() {
+        Ok(token) => Ok(Some(token)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(format!("Failed to read stored account: {}", e)),
+    }
+}
+
+pub fn clear_refresh_token(provider: &str, account: &str) -> Result<(), String> {
+    let entry = keyring::Entry::new(&service_name(provider), account)
+        .map_err(|e| format!("Failed to access keyring: {}", e))?;
+    match entry.delete_password() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(format!("Failed to clear stored account: {}", e)),
+    }
+}
@@ -1,3 +1,5 @@
+mod keyring_store;
+
 use std::sync::{Arc, Mutex};
 use std::net::TcpListener;
 use tauri::Manager;
@@ -12,6 +14,17 @@ struct OAuthResult {
     code_verifier: String,
 }
 
+#[derive(serde::Serialize, serde::Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: Option<String>,
+    // GitHub's /login/oauth/access_token doesn't return these by default.
+    #[serde(default)]
+    expires_in: Option<u64>,
+    #[serde(default)]
+    token_type: Option<String>,
+}
+
 fn generate_code_verifier() -> String {
     let random_bytes: Vec<u8> = (0..32).map(|_| rand::thread_rng().gen::<u8>()).collect();
     URL_SAFE_NO_PAD.encode(random_bytes)
@@ -24,58 +37,229 @@ fn generate_code_challenge(verifier: &str) -> String {
     URL_SAFE_NO_PAD.encode(result)
 }
 
+fn generate_state() -> String {
+    let random_bytes: Vec<u8> = (0..32).map(|_| rand::thread_rng().gen::<u8>()).collect();
+    URL_SAFE_NO_PAD.encode(random_bytes)
+}
+
+/// An OAuth/OIDC identity provider the app can authenticate against.
+///
+/// Each provider supplies the endpoints and any extra authorize-URL params
+/// it needs on top of the common PKCE + state parameters, so `start_oauth_flow`
+/// and the token-exchange commands stay provider-agnostic.
+#[derive(Clone, Copy)]
+enum Provider {
+    Google,
+    Microsoft,
+    GitHub,
+}
+
+impl Provider {
+    fn from_id(id: &str) -> Result<Self, String> {
+        match id {
+            "google" => Ok(Provider::Google),
+            "microsoft" => Ok(Provider::Microsoft),
+            "github" => Ok(Provider::GitHub),
+            other => Err(format!("Unknown OAuth provider: {}", other)),
+        }
+    }
+
+    fn auth_url(&self) -> &'static str {
+        match self {
+            Provider::Google => "https://accounts.google.com/o/oauth2/v2/auth",
+            Provider::Microsoft => "https://login.microsoftonline.com/common/oauth2/v2.0/authorize",
+            Provider::GitHub => "https://github.com/login/oauth/authorize",
+        }
+    }
+
+    fn token_url(&self) -> &'static str {
+        match self {
+            Provider::Google => "https://oauth2.googleapis.com/token",
+            Provider::Microsoft => "https://login.microsoftonline.com/common/oauth2/v2.0/token",
+            Provider::GitHub => "https://github.com/login/oauth/access_token",
+        }
+    }
+
+    /// Extra authorize-URL params beyond `client_id`/`redirect_uri`/`response_type`/
+    /// `scope`/`code_challenge`/`state`, which are common to every provider.
+    fn extra_auth_params(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Provider::Google => &[("access_type", "offline"), ("prompt", "consent")],
+            Provider::Microsoft => &[("response_mode", "query")],
+            Provider::GitHub => &[],
+        }
+    }
+
+    fn device_auth_url(&self) -> &'static str {
+        match self {
+            Provider::Google => "https://oauth2.googleapis.com/device/code",
+            Provider::Microsoft => "https://login.microsoftonline.com/common/oauth2/v2.0/devicecode",
+            Provider::GitHub => "https://github.com/login/device/code",
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct RawDeviceCodeResponse {
+    device_code: String,
+    user_code: String,
+    #[serde(alias = "verification_uri")]
+    verification_url: String,
+    expires_in: u64,
+    interval: u64,
+}
+
+#[derive(serde::Serialize)]
+struct DeviceAuthResult {
+    device_code: String,
+    user_code: String,
+    verification_url: String,
+    expires_in: u64,
+    interval: u64,
+}
+
+#[derive(serde::Deserialize)]
+struct DeviceErrorResponse {
+    error: String,
+}
+
+/// Candidate loopback ports for the OAuth redirect listener, tried in order.
+/// Register all of them as allowed redirect URIs in each provider's console
+/// so the flow still works if the first port is already taken.
+const LOOPBACK_PORTS: &[u16] = &[3737, 12731, 32492, 56909];
+
+fn bind_loopback_listener() -> Result<(TcpListener, u16), String> {
+    for port in LOOPBACK_PORTS {
+        if let Ok(listener) = TcpListener::bind(format!("127.0.0.1:{}", port)) {
+            return Ok((listener, *port));
+        }
+    }
+    Err(format!(
+        "Failed to bind any loopback port in {:?}. Make sure at least one of them is not in use.",
+        LOOPBACK_PORTS
+    ))
+}
+
 #[tauri::command]
-async fn start_oauth_flow(client_id: String, scopes: String) -> Result<OAuthResult, String> {
-    // Use fixed port 3737 for OAuth redirect
-    let port = 3737;
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", port))
-        .map_err(|e| format!("Failed to bind port {}: {}. Make sure port {} is not in use.", port, e, port))?;
+fn loopback_redirect_ports() -> Vec<u16> {
+    LOOPBACK_PORTS.to_vec()
+}
+
+fn html_response(status_code: u16, body: String) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+    let content_type = tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"text/html; charset=utf-8"[..])
+        .expect("static header is valid");
+    tiny_http::Response::from_string(body)
+        .with_status_code(status_code)
+        .with_header(content_type)
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
 
+fn success_page() -> String {
+    "<!DOCTYPE html><html><head><title>Signed in</title><style>\
+body{font-family:-apple-system,BlinkMacSystemFont,sans-serif;background:#0f172a;color:#e2e8f0;\
+display:flex;align-items:center;justify-content:center;height:100vh;margin:0}\
+div{text-align:center}h1{color:#4ade80}\
+</style></head><body><div><h1>Authentication successful</h1>\
+<p>You can close this window and return to the app.</p></div></body></html>"
+        .to_string()
+}
+
+fn error_page(message: &str) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><title>Sign-in failed</title><style>\
+body{{font-family:-apple-system,BlinkMacSystemFont,sans-serif;background:#0f172a;color:#e2e8f0;\
+display:flex;align-items:center;justify-content:center;height:100vh;margin:0}}\
+div{{text-align:center}}h1{{color:#f87171}}\
+</style></head><body><div><h1>Authentication failed</h1><p>{}</p></div></body></html>",
+        html_escape(message)
+    )
+}
+
+/// Parses the OAuth redirect callback's query string and decides the outcome:
+/// `Ok(code)` if the request carries a `code` whose `state` matches
+/// `expected_state`, otherwise `Err` with a message describing why (a
+/// provider-reported `error`, a state mismatch, or a missing `code`).
+fn handle_oauth_callback(url: &str, expected_state: &str) -> Result<String, String> {
+    let mut received_code = None;
+    let mut received_state = None;
+    let mut received_error = None;
+    let mut received_error_description = None;
+    if let Some(query) = url.split('?').nth(1) {
+        for param in query.split('&') {
+            if let Some((key, value)) = param.split_once('=') {
+                let decoded = urlencoding::decode(value).unwrap_or_default().to_string();
+                match key {
+                    "code" => received_code = Some(decoded),
+                    "state" => received_state = Some(decoded),
+                    "error" => received_error = Some(decoded),
+                    "error_description" => received_error_description = Some(decoded),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    if let Some(error) = received_error {
+        return Err(received_error_description.unwrap_or(error));
+    }
+    if received_state.as_deref() != Some(expected_state) {
+        return Err("Invalid or missing state parameter".to_string());
+    }
+    received_code.ok_or_else(|| "Callback did not include an authorization code".to_string())
+}
+
+#[tauri::command]
+async fn start_oauth_flow(provider: String, client_id: String, scopes: String) -> Result<OAuthResult, String> {
+    let provider = Provider::from_id(&provider)?;
+
+    let (listener, port) = bind_loopback_listener()?;
     let redirect_uri = format!("http://localhost:{}", port);
 
     // Generate PKCE parameters
     let code_verifier = generate_code_verifier();
     let code_challenge = generate_code_challenge(&code_verifier);
 
-    // Build OAuth URL with PKCE
-    let auth_url = format!(
-        "https://accounts.google.com/o/oauth2/v2/auth?client_id={}&redirect_uri={}&response_type=code&scope={}&access_type=offline&prompt=consent&code_challenge={}&code_challenge_method=S256",
+    // Generate anti-forgery state to bind the callback to this flow
+    let state = generate_state();
+
+    // Build OAuth URL with PKCE plus any provider-specific params
+    let mut auth_url = format!(
+        "{}?client_id={}&redirect_uri={}&response_type=code&scope={}&code_challenge={}&code_challenge_method=S256&state={}",
+        provider.auth_url(),
         client_id,
         urlencoding::encode(&redirect_uri),
         urlencoding::encode(&scopes),
-        urlencoding::encode(&code_challenge)
+        urlencoding::encode(&code_challenge),
+        urlencoding::encode(&state)
     );
+    for (key, value) in provider.extra_auth_params() {
+        auth_url.push_str(&format!("&{}={}", key, urlencoding::encode(value)));
+    }
 
-    // Store the auth code
-    let auth_code = Arc::new(Mutex::new(None));
-    let auth_code_clone = auth_code.clone();
+    // Store the result of the callback (the auth code, or an error)
+    let auth_result: Arc<Mutex<Option<Result<String, String>>>> = Arc::new(Mutex::new(None));
+    let auth_result_clone = auth_result.clone();
 
     // Start HTTP server in background
-    let redirect_uri_clone = redirect_uri.clone();
+    let expected_state = state.clone();
     std::thread::spawn(move || {
         let server = tiny_http::Server::from_listener(listener, None).unwrap();
 
+        // Handling exactly one request (success, failure, or malformed) then
+        // letting the thread exit drops `listener`, so the port is free for
+        // the next flow immediately rather than lingering until GC.
         if let Ok(request) = server.recv() {
-            let url = request.url();
-
-            // Parse query parameters
-            if let Some(query) = url.split('?').nth(1) {
-                for param in query.split('&') {
-                    if let Some((key, value)) = param.split_once('=') {
-                        if key == "code" {
-                            let decoded = urlencoding::decode(value).unwrap_or_default();
-                            *auth_code_clone.lock().unwrap() = Some(decoded.to_string());
-
-                            // Send success response
-                            let response = tiny_http::Response::from_string(
-                                "Authentication successful! You can close this window and return to the app."
-                            );
-                            let _ = request.respond(response);
-                            break;
-                        }
-                    }
-                }
-            }
+            let outcome = handle_oauth_callback(request.url(), &expected_state);
+
+            let response = match &outcome {
+                Ok(_) => html_response(200, success_page()),
+                Err(message) => html_response(400, error_page(message)),
+            };
+            *auth_result_clone.lock().unwrap() = Some(outcome);
+            let _ = request.respond(response);
         }
     });
 
@@ -85,23 +269,243 @@ async fn start_oauth_flow(client_id: String, scopes: String) -> Result<OAuthResu
     // Wait for auth code (with timeout)
     for _ in 0..60 {
         std::thread::sleep(std::time::Duration::from_secs(1));
-        if let Some(code) = auth_code.lock().unwrap().as_ref() {
-            return Ok(OAuthResult {
-                code: code.clone(),
-                redirect_uri,
-                code_verifier,
-            });
+        if let Some(result) = auth_result.lock().unwrap().as_ref() {
+            return match result {
+                Ok(code) => Ok(OAuthResult {
+                    code: code.clone(),
+                    redirect_uri,
+                    code_verifier,
+                }),
+                Err(e) => Err(e.clone()),
+            };
         }
     }
 
     Err("OAuth timeout: No response received".to_string())
 }
 
+#[tauri::command]
+async fn exchange_code_for_tokens(
+    provider: String,
+    client_id: String,
+    // Confidential clients (e.g. GitHub's OAuth App token endpoint, which is
+    // not a PKCE-only public-client endpoint) require this in addition to PKCE.
+    client_secret: Option<String>,
+    account: String,
+    code: String,
+    redirect_uri: String,
+    code_verifier: String,
+) -> Result<TokenResponse, String> {
+    let parsed_provider = Provider::from_id(&provider)?;
+    let mut params = vec![
+        ("grant_type", "authorization_code"),
+        ("client_id", client_id.as_str()),
+        ("code", code.as_str()),
+        ("redirect_uri", redirect_uri.as_str()),
+        ("code_verifier", code_verifier.as_str()),
+    ];
+    if let Some(secret) = &client_secret {
+        params.push(("client_secret", secret.as_str()));
+    }
+
+    let response = reqwest::Client::new()
+        .post(parsed_provider.token_url())
+        .header("Accept", "application/json")
+        .form(&params)
+        .send()
+        .await
+        .map_err(|e| format!("Token exchange request failed: {}", e))?;
+
+    if !response.status().is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(format!("Token exchange failed: {}", body));
+    }
+
+    let token_response = response
+        .json::<TokenResponse>()
+        .await
+        .map_err(|e| format!("Failed to parse token response: {}", e))?;
+
+    if let Some(refresh_token) = &token_response.refresh_token {
+        keyring_store::store_refresh_token(&provider, &account, refresh_token)?;
+    }
+
+    Ok(token_response)
+}
+
+#[tauri::command]
+async fn refresh_access_token(
+    provider: String,
+    client_id: String,
+    client_secret: Option<String>,
+    account: String,
+    refresh_token: String,
+) -> Result<TokenResponse, String> {
+    let parsed_provider = Provider::from_id(&provider)?;
+    let mut params = vec![
+        ("grant_type", "refresh_token"),
+        ("client_id", client_id.as_str()),
+        ("refresh_token", refresh_token.as_str()),
+    ];
+    if let Some(secret) = &client_secret {
+        params.push(("client_secret", secret.as_str()));
+    }
+
+    let response = reqwest::Client::new()
+        .post(parsed_provider.token_url())
+        .header("Accept", "application/json")
+        .form(&params)
+        .send()
+        .await
+        .map_err(|e| format!("Token refresh request failed: {}", e))?;
+
+    if !response.status().is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(format!("Token refresh failed: {}", body));
+    }
+
+    let token_response = response
+        .json::<TokenResponse>()
+        .await
+        .map_err(|e| format!("Failed to parse token response: {}", e))?;
+
+    // Some providers (Microsoft AAD, and Google under several configs) rotate
+    // the refresh token on every use and invalidate the old one, so the
+    // keyring entry must be updated here too or the next silent refresh fails.
+    if let Some(refresh_token) = &token_response.refresh_token {
+        keyring_store::store_refresh_token(&provider, &account, refresh_token)?;
+    }
+
+    Ok(token_response)
+}
+
+/// Starts a Device Authorization Grant (RFC 8628) flow for environments
+/// where `start_oauth_flow`'s loopback listener and browser launch don't
+/// work, e.g. over SSH or in a kiosk/container.
+#[tauri::command]
+async fn start_device_flow(provider: String, client_id: String, scopes: String) -> Result<DeviceAuthResult, String> {
+    let parsed_provider = Provider::from_id(&provider)?;
+    let params = [("client_id", client_id.as_str()), ("scope", scopes.as_str())];
+
+    let response = reqwest::Client::new()
+        .post(parsed_provider.device_auth_url())
+        .header("Accept", "application/json")
+        .form(&params)
+        .send()
+        .await
+        .map_err(|e| format!("Device authorization request failed: {}", e))?;
+
+    if !response.status().is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(format!("Device authorization failed: {}", body));
+    }
+
+    let raw = response
+        .json::<RawDeviceCodeResponse>()
+        .await
+        .map_err(|e| format!("Failed to parse device authorization response: {}", e))?;
+
+    Ok(DeviceAuthResult {
+        device_code: raw.device_code,
+        user_code: raw.user_code,
+        verification_url: raw.verification_url,
+        expires_in: raw.expires_in,
+        interval: raw.interval,
+    })
+}
+
+/// Polls the token endpoint for a Device Authorization Grant until the user
+/// approves the request, the grant is denied, or `expires_in` elapses.
+#[tauri::command]
+async fn poll_device_token(
+    provider: String,
+    client_id: String,
+    account: String,
+    device_code: String,
+    interval: u64,
+    expires_in: u64,
+) -> Result<TokenResponse, String> {
+    let parsed_provider = Provider::from_id(&provider)?;
+    let client = reqwest::Client::new();
+
+    let mut poll_interval = interval.max(1);
+    let mut elapsed = 0;
+
+    while elapsed < expires_in {
+        tokio::time::sleep(std::time::Duration::from_secs(poll_interval)).await;
+        elapsed += poll_interval;
+
+        let params = [
+            ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+            ("client_id", client_id.as_str()),
+            ("device_code", device_code.as_str()),
+        ];
+
+        let response = client
+            .post(parsed_provider.token_url())
+            .header("Accept", "application/json")
+            .form(&params)
+            .send()
+            .await
+            .map_err(|e| format!("Device token poll failed: {}", e))?;
+
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .map_err(|e| format!("Failed to read device token response: {}", e))?;
+
+        if status.is_success() {
+            let token_response = serde_json::from_str::<TokenResponse>(&body)
+                .map_err(|e| format!("Failed to parse token response: {}", e))?;
+
+            if let Some(refresh_token) = &token_response.refresh_token {
+                keyring_store::store_refresh_token(&provider, &account, refresh_token)?;
+            }
+
+            return Ok(token_response);
+        }
+
+        match serde_json::from_str::<DeviceErrorResponse>(&body) {
+            Ok(err) if err.error == "authorization_pending" => continue,
+            Ok(err) if err.error == "slow_down" => {
+                poll_interval += 5;
+                continue;
+            }
+            Ok(err) => return Err(format!("Device authorization failed: {}", err.error)),
+            Err(_) => return Err(format!("Device token poll failed: {}", body)),
+        }
+    }
+
+    Err("Device authorization timed out".to_string())
+}
+
+#[tauri::command]
+async fn load_stored_account(provider: String, account: String) -> Result<Option<String>, String> {
+    Provider::from_id(&provider)?;
+    keyring_store::load_refresh_token(&provider, &account)
+}
+
+#[tauri::command]
+async fn clear_stored_account(provider: String, account: String) -> Result<(), String> {
+    Provider::from_id(&provider)?;
+    keyring_store::clear_refresh_token(&provider, &account)
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
   tauri::Builder::default()
     .plugin(tauri_plugin_shell::init())
-    .invoke_handler(tauri::generate_handler![start_oauth_flow])
+    .invoke_handler(tauri::generate_handler![
+      loopback_redirect_ports,
+      start_oauth_flow,
+      exchange_code_for_tokens,
+      refresh_access_token,
+      start_device_flow,
+      poll_device_token,
+      load_stored_account,
+      clear_stored_account
+    ])
     .setup(|app| {
       if cfg!(debug_assertions) {
         app.handle().plugin(
@@ -115,3 +519,44 @@ pub fn run() {
     .run(tauri::generate_context!())
     .expect("error while running tauri application");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_code_when_state_matches() {
+        let outcome = handle_oauth_callback("/?code=abc123&state=xyz", "xyz");
+        assert_eq!(outcome, Ok("abc123".to_string()));
+    }
+
+    #[test]
+    fn rejects_mismatched_state() {
+        let outcome = handle_oauth_callback("/?code=abc123&state=wrong", "xyz");
+        assert_eq!(outcome, Err("Invalid or missing state parameter".to_string()));
+    }
+
+    #[test]
+    fn rejects_missing_state() {
+        let outcome = handle_oauth_callback("/?code=abc123", "xyz");
+        assert_eq!(outcome, Err("Invalid or missing state parameter".to_string()));
+    }
+
+    #[test]
+    fn surfaces_provider_error() {
+        let outcome = handle_oauth_callback(
+            "/?error=access_denied&error_description=user_declined&state=xyz",
+            "xyz",
+        );
+        assert_eq!(outcome, Err("user_declined".to_string()));
+    }
+
+    #[test]
+    fn rejects_missing_code() {
+        let outcome = handle_oauth_callback("/?state=xyz", "xyz");
+        assert_eq!(
+            outcome,
+            Err("Callback did not include an authorization code".to_string())
+        );
+    }
+}